@@ -1,5 +1,7 @@
 // Copyright (c) Microsoft. All rights reserved.
 extern crate cmake;
+extern crate pkg_config;
+extern crate vcpkg;
 
 use std::fs;
 use std::path::PathBuf;
@@ -13,57 +15,76 @@ use cmake::Config;
 const SSL_OPTION: &str = "use_openssl";
 const USE_EMULATOR: &str = "use_emulator";
 
+// `target` is `Build`'s own target triple (defaulted from `TARGET`, but
+// overridable via `Build::target`), not the `#[cfg(windows)]`/`#[cfg(unix)]`
+// the *host* running the build script would report, so cross-compiling the
+// HSM (e.g. for ARM TrustZone, or for Windows from a non-Windows host) picks
+// the right branch. Cargo always sets `CARGO_CFG_WINDOWS`/`CARGO_CFG_TARGET_ENV`
+// for the *real* build, independent of anything `Build::target()` overrides,
+// and they're authoritative where a target triple's own suffix is ambiguous
+// (e.g. `CARGO_CFG_TARGET_ENV` is "musl" for `armv7-unknown-linux-musleabihf`,
+// not the "musleabihf" a naive suffix split would read off). Only fall back to
+// parsing `target` itself once it no longer matches the real `TARGET`, i.e.
+// once something has explicitly overridden it away from the actual build.
+fn target_is_windows(target: &str) -> bool {
+    if target == env::var("TARGET").unwrap_or_default() {
+        env::var("CARGO_CFG_WINDOWS").is_ok()
+    } else {
+        target.contains("windows")
+    }
+}
+
+fn target_env(target: &str) -> String {
+    if target == env::var("TARGET").unwrap_or_default() {
+        env::var("CARGO_CFG_TARGET_ENV").unwrap_or_default()
+    } else {
+        target.rsplit('-').next().unwrap_or("").to_string()
+    }
+}
+
 trait SetPlatformDefines {
-    fn set_platform_defines(&mut self) -> &mut Self;
+    fn set_platform_defines(&mut self, target: &str, run_valgrind: bool) -> &mut Self;
     fn set_build_shared(&mut self) -> &mut Self;
 }
 
 trait SetEnclaveDefines {
-    fn set_enclave_options(&mut self) -> &mut Self;
+    fn set_enclave_options(&mut self, target: &str, use_enclave: bool) -> &mut Self;
 }
 
 impl SetPlatformDefines for Config {
-    #[cfg(windows)]
-    fn set_platform_defines(&mut self) -> &mut Self {
-        // if the builder chooses to set "use_emulator", use their setting, otherwise, use the
-        // emulator for debug and a real device for release
-        let use_emulator = env::var(USE_EMULATOR)
-            .or_else(|_| {
-                env::var("PROFILE").and_then(|profile| {
-                    Ok(if profile.to_lowercase() == "release" {
-                        String::from("OFF")
-                    } else {
-                        String::from("ON")
+    fn set_platform_defines(&mut self, target: &str, run_valgrind: bool) -> &mut Self {
+        if target_is_windows(target) {
+            // if the builder chooses to set "use_emulator", use their setting, otherwise, use the
+            // emulator for debug and a real device for release
+            let use_emulator = env::var(USE_EMULATOR)
+                .or_else(|_| {
+                    env::var("PROFILE").map(|profile| {
+                        if profile.to_lowercase() == "release" {
+                            String::from("OFF")
+                        } else {
+                            String::from("ON")
+                        }
                     })
                 })
-            })
-            .unwrap();
-        // C-shared library wants Windows flags (/DWIN32 /D_WINDOWS) for Windows,
-        // and the cmake library overrides this.
-        self.cflag("/DWIN32")
-            .cxxflag("/DWIN32")
-            .cflag("/D_WINDOWS")
-            .cxxflag("/D_WINDOWS")
-            .build_arg("/m")
-            .define(USE_EMULATOR, use_emulator)
-            .define("use_cppunittest", "OFF")
-    }
-
-    #[cfg(unix)]
-    fn set_platform_defines(&mut self) -> &mut Self {
-        let rv = if env::var("TARGET").unwrap().starts_with("x86_64")
-            && env::var("RUN_VALGRIND").is_ok()
-        {
-            "ON"
-        } else {
-            "OFF"
-        };
-        if let Ok(sysroot) = env::var("SYSROOT") {
-            self.define("run_valgrind", rv)
-                .define("CMAKE_SYSROOT", sysroot)
-                .define(USE_EMULATOR, "OFF")
+                .unwrap();
+            // C-shared library wants Windows flags (/DWIN32 /D_WINDOWS) for Windows,
+            // and the cmake library overrides this.
+            self.cflag("/DWIN32")
+                .cxxflag("/DWIN32")
+                .cflag("/D_WINDOWS")
+                .cxxflag("/D_WINDOWS")
+                .build_arg("/m")
+                .define(USE_EMULATOR, use_emulator)
+                .define("use_cppunittest", "OFF")
         } else {
-            self.define("run_valgrind", rv).define(USE_EMULATOR, "OFF")
+            let rv = if run_valgrind { "ON" } else { "OFF" };
+            if let Ok(sysroot) = env::var("SYSROOT") {
+                self.define("run_valgrind", rv)
+                    .define("CMAKE_SYSROOT", sysroot)
+                    .define(USE_EMULATOR, "OFF")
+            } else {
+                self.define("run_valgrind", rv).define(USE_EMULATOR, "OFF")
+            }
         }
     }
 
@@ -80,228 +101,606 @@ impl SetPlatformDefines for Config {
     }
 }
 
+// musl forbids the kind of runtime dynamic loading the TEE host libraries
+// (Open Enclave, the Intel SGX PSW, OP-TEE client libs) rely on, the same
+// glibc-vs-musl split other HSM-capable crates draw.
+fn fail_if_musl_enclave(target: &str, use_enclave: bool) {
+    if use_enclave && target_env(target) == "musl" {
+        panic!(
+            "The enclave-sgx/enclave-tz features require dynamic loading support \
+             that musl targets do not provide; build against a glibc target instead"
+        );
+    }
+}
+
 impl SetEnclaveDefines for Config {
-    #[cfg(windows)]
-    fn set_enclave_options(&mut self) -> &mut Self {
-        if env::var("USE_ENCLAVE").is_ok() {
-            let tee = env::var("USE_ENCLAVE").unwrap().to_lowercase();
-            let use_simulation = env::var("USE_SIMULATION").is_ok();
-
-            if tee == "intel sgx" || tee == "sgx" {
-                self.define("OE_TEE", "SGX")
-                    .define("use_enclave", "ON");
-
-                if use_simulation {
-                    self.define("OE_USE_SIMULATION", "ON")
-                } else {
-                    self
-                }
-            } else {
-                panic!("Building the HSM enclave on Windows is currently only supported for Intel SGX");
-            }
-        } else {
-            self
+    fn set_enclave_options(&mut self, target: &str, use_enclave: bool) -> &mut Self {
+        if !use_enclave {
+            return self;
         }
-    }
 
-    #[cfg(unix)]
-    fn set_enclave_options(&mut self) -> &mut Self {
-        if env::var("USE_ENCLAVE").is_ok() {
-            let tee = env::var("USE_ENCLAVE").unwrap().to_lowercase();
-            let use_simulation = env::var("USE_SIMULATION").is_ok();
+        let use_simulation = cfg!(feature = "simulation");
 
-            if tee == "arm trustzone" || tee == "tz" {
-                if use_simulation {
-                    panic!("Simulation builds are not yet supported for ARM TrustZone on Linux");
-                }
+        if target_is_windows(target) {
+            if !cfg!(feature = "enclave-sgx") {
+                panic!("Building the HSM enclave on Windows is currently only supported for Intel SGX (feature \"enclave-sgx\")");
+            }
 
-                let ta_dev_kit_path = PathBuf::from("azure-iot-hsm-c/deps/optee/ta_dev_kit");
-                let ta_dev_kit_abs_path = fs::canonicalize(&ta_dev_kit_path);
+            self.define("OE_TEE", "SGX").define("use_enclave", "ON");
 
-                self.define("OE_TEE", "TZ")
-                    .define("TA_DEV_KIT_DIR", ta_dev_kit_abs_path.unwrap().to_str().unwrap())
-                    .define("use_enclave", "ON")
+            if use_simulation {
+                self.define("OE_USE_SIMULATION", "ON")
             } else {
-                panic!("Building the HSM enclave on Linux is currently only supported for ARM TrustZone");
+                self
             }
         } else {
-            self
+            if !cfg!(feature = "enclave-tz") {
+                panic!("Building the HSM enclave on Linux is currently only supported for ARM TrustZone (feature \"enclave-tz\")");
+            }
+            if use_simulation {
+                panic!("Simulation builds are not yet supported for ARM TrustZone on Linux");
+            }
+
+            let ta_dev_kit_path = PathBuf::from("azure-iot-hsm-c/deps/optee/ta_dev_kit");
+            let ta_dev_kit_abs_path = fs::canonicalize(&ta_dev_kit_path);
+
+            self.define("OE_TEE", "TZ")
+                .define("TA_DEV_KIT_DIR", ta_dev_kit_abs_path.unwrap().to_str().unwrap())
+                .define("use_enclave", "ON")
         }
     }
 }
 
-fn main() {
-    // Clone Azure C -shared library
-    let c_shared_repo = "azure-iot-hsm-c/deps/c-shared";
-    let utpm_repo = "azure-iot-hsm-c/deps/utpm";
-    let oe_repo = "azure-iot-hsm-c/deps/openenclave";
-    let oe_new_platforms = format!("{}/new_platforms", oe_repo);
-
-    let use_enclave = if env::var("USE_ENCLAVE").is_ok() {
-        true
-    } else {
-        false
-    };
-
-    println!("#Start Update C-Shared Utilities");
-    if !Path::new(&format!("{}/.git", c_shared_repo)).exists()
-        || !Path::new(&format!("{}/.git", utpm_repo)).exists()
-        || (use_enclave && !Path::new(&format!("{}/.git", oe_repo)).exists())
-    {
-        let _ = Command::new("git")
-            .arg("submodule")
-            .arg("update")
-            .arg("--init")
-            .arg("--recursive")
-            .status()
-            .expect("submodule update failed");
+// When set, look for a pre-installed iothsm/azure-iot-c stack via pkg-config
+// instead of cloning submodules and building everything from source. This is
+// an opt-in escape hatch for developers/distros that already ship these
+// libraries system-wide.
+const PKG_CONFIG_ENV: &str = "IOTHSM_SYS_USE_PKG_CONFIG";
+
+// Tries to satisfy the build entirely from system-installed libraries found
+// via pkg-config. Returns the resulting `Artifacts` on success, in which
+// case `Build::build` should return early without building anything. Any
+// pkg-config failure falls back to the from-source build.
+fn try_use_pkg_config() -> Option<Artifacts> {
+    let lib_names = ["iothsm", "aziotsharedutil", "utpm", "crypto"];
+    let mut found = Vec::new();
+    for lib in &lib_names {
+        // `probe()` would otherwise print its own cargo:rustc-link-lib/
+        // rustc-link-search directives for every library found; instead
+        // main() prints the link-lib directives and `iothsm`'s search and
+        // include path from the Artifacts this function returns, and the
+        // other libraries' paths are printed below.
+        match pkg_config::Config::new().cargo_metadata(false).probe(lib) {
+            Ok(library) => found.push(library),
+            Err(err) => {
+                println!(
+                    "cargo:warning=pkg-config could not find {}: {}; falling back to building from source",
+                    lib, err
+                );
+                return None;
+            }
+        }
     }
 
-    println!("#Done Updating C-Shared Utilities");
-
-    println!("#Start building shared utilities");
-    let _shared = Config::new(c_shared_repo)
-        .define(SSL_OPTION, "ON")
-        .define("CMAKE_BUILD_TYPE", "Release")
-        .define("run_unittests", "OFF")
-        .define("use_default_uuid", "ON")
-        .define("use_http", "OFF")
-        .define("skip_samples", "ON")
-        .set_platform_defines()
-        .define("run_valgrind", "OFF")
-        .profile("Release")
-        .build();
-
-    println!("#Also build micro tpm library");
-    let _shared = Config::new(utpm_repo)
-        .define(SSL_OPTION, "ON")
-        .define("CMAKE_BUILD_TYPE", "Release")
-        .define("run_unittests", "OFF")
-        .define("use_default_uuid", "ON")
-        .define("use_http", "OFF")
-        .define("skip_samples", "ON")
-        .set_platform_defines()
-        .define("run_valgrind", "OFF")
-        .profile("Release")
-        .build();
-
-    if use_enclave {
-        #[cfg(unix)]
-        {
-            // Using an enclave on Unix currently implies building for ARM and
-            // TrustZone as the Trusted Execution Technology (TEE). As such,
-            // this build script will execute inside the cross-compilation
-            // container that Cross spawns during build.
-            println!("#Install PIP");
-            Command::new("apt-get")
-                .arg("install")
-                .arg("python-pip")
-                .arg("python-dev")
-                .arg("libgmp3-dev")
-                .arg("-y")
-                .status()
-                .expect("apt-get install failed");
-
-            println!("#Install PyCrypto");
-            Command::new("pip")
-                .arg("install")
-                .arg("-i")
-                .arg("https://pypi.python.org/simple/")
-                .arg("pycrypto")
-                .current_dir("/target")
-                .status()
-                .expect("pip install pycrypto failed");
+    // `Artifacts` holds a single library directory and include directory,
+    // so `iothsm`'s first search path and include path go there and the
+    // other libraries' paths are printed here directly; `libs` lists the
+    // link names of every library found.
+    let iothsm = &found[0];
+    for library in &found[1..] {
+        for path in &library.link_paths {
+            println!("cargo:rustc-link-search=native={}", path.display());
+        }
+        for path in &library.include_paths {
+            println!("cargo:include={}", path.display());
         }
+    }
+    let libs = found.iter().flat_map(|l| l.libs.iter().cloned()).collect();
 
-        println!("#And build the Open Enclave SDK");
-        let _shared = Config::new(oe_new_platforms)
-            .set_enclave_options()
-            .set_platform_defines()
-            .profile("Release")
-            .build();
+    Some(Artifacts {
+        lib_dir: iothsm.link_paths.first().cloned().unwrap_or_default(),
+        include_dir: iothsm.include_paths.first().cloned().unwrap_or_default(),
+        libs,
+    })
+}
+
+// Selects how `Build::build` should obtain the `iothsm` libraries: compile
+// them from the submodules (the default and the only strategy that always
+// works), look for them on the system the way `IOTHSM_SYS_USE_PKG_CONFIG`
+// already does, or download a prebuilt, checksum-verified archive to skip
+// compiling the C/enclave stack altogether. This dominates build time,
+// especially in CI and for the enclave path.
+const STRATEGY_ENV: &str = "IOTHSM_STRATEGY";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Strategy {
+    Compile,
+    System,
+    Download,
+}
+
+fn strategy() -> Strategy {
+    match env::var(STRATEGY_ENV).as_deref() {
+        Ok("system") => Strategy::System,
+        Ok("download") => Strategy::Download,
+        _ => Strategy::Compile,
     }
+}
 
-    // make the C libary at azure-iot-hsm-c (currently a subdirectory in this
-    // crate)
-    // Always make the Release version because Rust links to the Release CRT.
-    // (This is especially important for Windows)
+// A version-pinned prebuilt archive of the `iothsm` libraries for one
+// target triple, with the checksum it is expected to have.
+struct PrebuiltArchive {
+    url: &'static str,
+    sha256: &'static str,
+}
 
-    let rut = if env::var("FORCE_NO_UNITTEST").is_ok() {
-        "OFF"
-    } else {
-        "ON"
-    };
-
-    println!("#Start building HSM dev-mode library");
-    let iothsm = Config::new("azure-iot-hsm-c")
-        .define(SSL_OPTION, "ON")
-        .define("CMAKE_BUILD_TYPE", "Release")
-        .define("run_unittests", rut)
-        .define("use_default_uuid", "ON")
-        .define("use_http", "OFF")
-        .define("skip_samples", "ON")
-        .set_enclave_options()
-        .set_platform_defines()
-        .set_build_shared()
-        .profile("Release")
-        .build();
-
-    println!("#Done building HSM dev-mode library");
-
-    // where to find the library (The "link-lib" should match the library name
-    // defined in the CMakefile.txt)
-
-    println!("cargo:rerun-if-env-changed=RUN_VALGRIND");
-    // For libraries which will just install in target directory
-    println!("cargo:rustc-link-search=native={}", iothsm.display());
-    // For libraries (ie. C Shared) which will install in $target/lib
-    println!("cargo:rustc-link-search=native={}/lib", iothsm.display());
-    println!("cargo:rustc-link-search=native={}/lib64", iothsm.display());
-    println!("cargo:rustc-link-lib=iothsm");
-
-    // we need to explicitly link with c shared util only when we build the C
-    // library as a static lib which we do only in rust debug builds
-    #[cfg(debug_assertions)]
-    println!("cargo:rustc-link-lib=aziotsharedutil");
-    #[cfg(debug_assertions)]
-    println!("cargo:rustc-link-lib=utpm");
+const IOTHSM_PREBUILT_VERSION: &str = "1.3.0";
 
-    if use_enclave {
-        println!("cargo:rustc-link-lib=oesocket_host");
-        println!("cargo:rustc-link-lib=oestdio_host");
-        println!("cargo:rustc-link-lib=oehost");
+// No targets are wired up yet: there is no published 1.3.0 release archive
+// for `iothsm` to point at. Fill in `ARCHIVES_BY_TARGET` with the real
+// published URL and sha256 for each target this should support before
+// turning anyone loose on `IOTHSM_STRATEGY=download` — until then this
+// always falls back to compiling from source.
+const ARCHIVES_BY_TARGET: &[(&str, &str, &str)] = &[];
 
-        #[cfg(unix)]
-        println!("cargo:rustc-link-lib=teec");
+fn prebuilt_archive_for_target(target: &str) -> Option<PrebuiltArchive> {
+    ARCHIVES_BY_TARGET
+        .iter()
+        .find(|(t, _, _)| *t == target)
+        .map(|(_, url, sha256)| PrebuiltArchive { url, sha256 })
+}
+
+fn sha256_matches(path: &Path, expected: &str) -> bool {
+    let output = Command::new("sha256sum").arg(path).output();
+    match output {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .next()
+            == Some(expected),
+        _ => false,
     }
+}
 
-    #[cfg(windows)]
-    {
+// Downloads and unpacks the prebuilt `iothsm` archive for `target` into
+// `out_dir`, returning its `Artifacts` on success. Falls back (returns
+// `None`) on an unsupported triple, a failed download, or a checksum
+// mismatch, so the caller can compile from source instead.
+fn try_download_prebuilt(out_dir: &Path, target: &str) -> Option<Artifacts> {
+    let archive = prebuilt_archive_for_target(target).or_else(|| {
         println!(
-            "cargo:rustc-link-search=native={}/lib",
-            env::var("OPENSSL_ROOT_DIR").unwrap()
+            "cargo:warning=no prebuilt iothsm archive for target \"{}\"; falling back to compiling from source",
+            target
         );
-        println!("cargo:rustc-link-lib=libeay32");
-        println!("cargo:rustc-link-lib=ssleay32");
+        None
+    })?;
+
+    let archive_path = out_dir.join(format!("iothsm-{}.tar.gz", IOTHSM_PREBUILT_VERSION));
+    let downloaded = Command::new("curl")
+        .arg("--fail")
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--location")
+        .arg("--output")
+        .arg(&archive_path)
+        .arg(archive.url)
+        .status()
+        .is_ok_and(|status| status.success());
+
+    if !downloaded {
+        println!("cargo:warning=failed to download prebuilt iothsm archive; falling back to compiling from source");
+        return None;
+    }
 
-        println!(
-            "cargo:rustc-link-search=native={}/bin/x64/Release",
-            env::var("SGXSDKInstallPath").unwrap()
+    if !sha256_matches(&archive_path, archive.sha256) {
+        println!("cargo:warning=checksum mismatch for downloaded iothsm archive; falling back to compiling from source");
+        let _ = fs::remove_file(&archive_path);
+        return None;
+    }
+
+    let unpack_dir = out_dir.join("iothsm-prebuilt");
+    let _ = fs::remove_dir_all(&unpack_dir);
+    fs::create_dir_all(&unpack_dir).expect("failed to create directory for prebuilt iothsm archive");
+
+    let unpacked = Command::new("tar")
+        .arg("xzf")
+        .arg(&archive_path)
+        .arg("-C")
+        .arg(&unpack_dir)
+        .status()
+        .is_ok_and(|status| status.success());
+
+    if !unpacked {
+        println!("cargo:warning=failed to unpack prebuilt iothsm archive; falling back to compiling from source");
+        return None;
+    }
+
+    println!(
+        "cargo:rustc-link-search=native={}",
+        unpack_dir.join("lib").display()
+    );
+
+    Some(Artifacts {
+        lib_dir: unpack_dir.join("lib"),
+        include_dir: unpack_dir.join("include"),
+        libs: vec![
+            "iothsm".to_string(),
+            "aziotsharedutil".to_string(),
+            "utpm".to_string(),
+            "crypto".to_string(),
+        ],
+    })
+}
+
+// Runs `git submodule update --init --recursive` and then verifies that
+// each directory we actually depend on came through non-empty, panicking
+// with the exact command to run otherwise. The previous code ignored the
+// update's exit status entirely, so a failed or partial checkout (e.g. no
+// network access, or a detached submodule) surfaced as a confusing cmake
+// error deep in the build instead of at the source of the problem.
+fn update_and_verify_submodules(required_dirs: &[&str]) {
+    let _ = Command::new("git")
+        .arg("submodule")
+        .arg("update")
+        .arg("--init")
+        .arg("--recursive")
+        .status()
+        .expect("submodule update failed");
+
+    for dir in required_dirs {
+        let is_empty = fs::read_dir(dir)
+            .map(|mut entries| entries.next().is_none())
+            .unwrap_or(true);
+
+        if is_empty {
+            panic!(
+                "required submodule directory \"{}\" is missing or empty; run \
+                 `git submodule update --init --recursive` and try again",
+                dir
+            );
+        }
+    }
+}
+
+// On an MSVC target, prefer a vcpkg-managed OpenSSL (the common setup for
+// Windows developers): `vcpkg::find_package` locates it and emits its own
+// cargo:rustc-link-search/link-lib directives, picking whichever of the old
+// (libeay32/ssleay32) or modern (libcrypto/libssl) names the installed port
+// uses. Only fall back to OPENSSL_ROOT_DIR when vcpkg can't find it; if that
+// variable is unset too, panic with a message naming both options instead of
+// unwrap's bare error.
+fn link_openssl_windows(target: &str) {
+    if target_env(target) == "msvc" && vcpkg::find_package("openssl").is_ok() {
+        return;
+    }
+
+    let root = env::var("OPENSSL_ROOT_DIR").unwrap_or_else(|_| {
+        panic!(
+            "could not find OpenSSL via vcpkg and the OPENSSL_ROOT_DIR \
+             environment variable is not set; install OpenSSL through vcpkg \
+             or point OPENSSL_ROOT_DIR at an existing install"
+        )
+    });
+
+    println!("cargo:rustc-link-search=native={}/lib", root);
+    println!("cargo:rustc-link-lib=libeay32");
+    println!("cargo:rustc-link-lib=ssleay32");
+}
+
+/// Configuration for building the `iothsm` native library: setters for the
+/// output directory, target, host, enclave use, SSL backend and valgrind, and
+/// a `build()` that returns an `Artifacts` handle describing what to link.
+/// `build()` still reads some settings from the environment
+/// (`IOTHSM_STRATEGY`, `IOTHSM_SYS_USE_PKG_CONFIG`, `FORCE_NO_UNITTEST`,
+/// `SYSROOT`, `use_emulator`/`PROFILE`, and for Windows targets
+/// `OPENSSL_ROOT_DIR` and `SGXSDKInstallPath`), and it prints some cargo link
+/// directives itself: the extra search paths of the libraries it compiles,
+/// downloads or finds via pkg-config and, for Windows targets, the OpenSSL
+/// and SGX SDK ones, none of which `Artifacts` carries. This makes the HSM
+/// build usable from something other than this crate's own `main()`.
+pub struct Build {
+    out_dir: PathBuf,
+    target: String,
+    host: String,
+    use_enclave: bool,
+    ssl_backend: SslBackend,
+    run_valgrind: bool,
+}
+
+/// The only backend the C library currently supports; kept as an enum
+/// (rather than a bare bool) so adding another backend later doesn't
+/// change the `Build` API.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SslBackend {
+    OpenSsl,
+}
+
+/// The result of a successful `Build::build()`: where the caller should
+/// point `rustc-link-search`/`rustc-link-lib`/`cargo:include` at.
+pub struct Artifacts {
+    lib_dir: PathBuf,
+    include_dir: PathBuf,
+    libs: Vec<String>,
+}
+
+impl Default for Build {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Build {
+    pub fn new() -> Build {
+        Build {
+            out_dir: env::var_os("OUT_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_default(),
+            target: env::var("TARGET").unwrap_or_default(),
+            host: env::var("HOST").unwrap_or_default(),
+            use_enclave: cfg!(feature = "enclave-sgx") || cfg!(feature = "enclave-tz"),
+            ssl_backend: SslBackend::OpenSsl,
+            run_valgrind: env::var("RUN_VALGRIND").is_ok(),
+        }
+    }
+
+    pub fn out_dir<P: AsRef<Path>>(&mut self, out_dir: P) -> &mut Build {
+        self.out_dir = out_dir.as_ref().to_path_buf();
+        self
+    }
+
+    pub fn target(&mut self, target: &str) -> &mut Build {
+        self.target = target.to_string();
+        self
+    }
+
+    pub fn host(&mut self, host: &str) -> &mut Build {
+        self.host = host.to_string();
+        self
+    }
+
+    pub fn use_enclave(&mut self, use_enclave: bool) -> &mut Build {
+        self.use_enclave = use_enclave;
+        self
+    }
+
+    pub fn ssl_backend(&mut self, ssl_backend: SslBackend) -> &mut Build {
+        self.ssl_backend = ssl_backend;
+        self
+    }
+
+    pub fn run_valgrind(&mut self, run_valgrind: bool) -> &mut Build {
+        self.run_valgrind = run_valgrind;
+        self
+    }
+
+    pub fn build(&mut self) -> Artifacts {
+        fail_if_musl_enclave(&self.target, self.use_enclave);
+
+        let strategy = strategy();
+
+        if strategy == Strategy::System || env::var(PKG_CONFIG_ENV).is_ok() {
+            if let Some(artifacts) = try_use_pkg_config() {
+                return artifacts;
+            }
+        }
+
+        if strategy == Strategy::Download {
+            if let Some(artifacts) = try_download_prebuilt(&self.out_dir, &self.target) {
+                return artifacts;
+            }
+        }
+
+        assert_eq!(
+            self.ssl_backend,
+            SslBackend::OpenSsl,
+            "only the OpenSSL backend is currently supported"
         );
 
+        // Clone Azure C -shared library
+        let c_shared_repo = "azure-iot-hsm-c/deps/c-shared";
+        let utpm_repo = "azure-iot-hsm-c/deps/utpm";
+        let oe_repo = "azure-iot-hsm-c/deps/openenclave";
+        let oe_new_platforms = format!("{}/new_platforms", oe_repo);
+
+        let use_enclave = self.use_enclave;
+        let target = self.target.clone();
+        let host = self.host.clone();
+        let run_valgrind = self.run_valgrind && target.starts_with("x86_64");
+
+        println!("#Start Update C-Shared Utilities");
+        if !Path::new(&format!("{}/.git", c_shared_repo)).exists()
+            || !Path::new(&format!("{}/.git", utpm_repo)).exists()
+            || (use_enclave && !Path::new(&format!("{}/.git", oe_repo)).exists())
+        {
+            let mut required_dirs = vec![c_shared_repo, utpm_repo];
+            if use_enclave {
+                required_dirs.push(oe_new_platforms.as_str());
+            }
+            update_and_verify_submodules(&required_dirs);
+        }
+
+        println!("#Done Updating C-Shared Utilities");
+
+        println!("#Start building shared utilities");
+        let _shared = Config::new(c_shared_repo)
+            .target(&target)
+            .host(&host)
+            .define(SSL_OPTION, "ON")
+            .define("CMAKE_BUILD_TYPE", "Release")
+            .define("run_unittests", "OFF")
+            .define("use_default_uuid", "ON")
+            .define("use_http", "OFF")
+            .define("skip_samples", "ON")
+            .set_platform_defines(&target, run_valgrind)
+            .define("run_valgrind", "OFF")
+            .profile("Release")
+            .build();
+
+        println!("#Also build micro tpm library");
+        let _shared = Config::new(utpm_repo)
+            .target(&target)
+            .host(&host)
+            .define(SSL_OPTION, "ON")
+            .define("CMAKE_BUILD_TYPE", "Release")
+            .define("run_unittests", "OFF")
+            .define("use_default_uuid", "ON")
+            .define("use_http", "OFF")
+            .define("skip_samples", "ON")
+            .set_platform_defines(&target, run_valgrind)
+            .define("run_valgrind", "OFF")
+            .profile("Release")
+            .build();
+
         if use_enclave {
-            if fs::copy(
-                format!("{}/bin/enc.signed.dll", iothsm.display()),
-                format!("{}/../../../deps/enc.signed.dll", iothsm.display())).is_err() {
-                panic!("Failed to copy enclave to output directory");
+            #[cfg(unix)]
+            {
+                // Using an enclave on Unix currently implies building for ARM and
+                // TrustZone as the Trusted Execution Technology (TEE). As such,
+                // this build script will execute inside the cross-compilation
+                // container that Cross spawns during build.
+                println!("#Install PIP");
+                Command::new("apt-get")
+                    .arg("install")
+                    .arg("python-pip")
+                    .arg("python-dev")
+                    .arg("libgmp3-dev")
+                    .arg("-y")
+                    .status()
+                    .expect("apt-get install failed");
+
+                println!("#Install PyCrypto");
+                Command::new("pip")
+                    .arg("install")
+                    .arg("-i")
+                    .arg("https://pypi.python.org/simple/")
+                    .arg("pycrypto")
+                    .current_dir("/target")
+                    .status()
+                    .expect("pip install pycrypto failed");
             }
 
-            println!("cargo:rustc-link-lib=sgx_urts_sim");
-            println!("cargo:rustc-link-lib=sgx_uae_service_sim");
-            println!("cargo:rustc-link-lib=sgx_uprotected_fs");
+            println!("#And build the Open Enclave SDK");
+            let _shared = Config::new(oe_new_platforms)
+                .target(&target)
+                .host(&host)
+                .set_enclave_options(&target, use_enclave)
+                .set_platform_defines(&target, run_valgrind)
+                .profile("Release")
+                .build();
         }
+
+        // make the C libary at azure-iot-hsm-c (currently a subdirectory in this
+        // crate)
+        // Always make the Release version because Rust links to the Release CRT.
+        // (This is especially important for Windows)
+
+        let rut = if env::var("FORCE_NO_UNITTEST").is_ok() {
+            "OFF"
+        } else {
+            "ON"
+        };
+
+        println!("#Start building HSM dev-mode library");
+        let iothsm = Config::new("azure-iot-hsm-c")
+            .target(&target)
+            .host(&host)
+            .define(SSL_OPTION, "ON")
+            .define("CMAKE_BUILD_TYPE", "Release")
+            .define("run_unittests", rut)
+            .define("use_default_uuid", "ON")
+            .define("use_http", "OFF")
+            .define("skip_samples", "ON")
+            .set_enclave_options(&target, use_enclave)
+            .set_platform_defines(&target, run_valgrind)
+            .set_build_shared()
+            .profile("Release")
+            .build();
+
+        println!("#Done building HSM dev-mode library");
+
+        // where to find the library (The "link-lib" should match the library name
+        // defined in the CMakefile.txt)
+
+        println!("cargo:rerun-if-env-changed=RUN_VALGRIND");
+
+        let mut libs = vec!["iothsm".to_string()];
+
+        // we need to explicitly link with c shared util only when we build the C
+        // library as a static lib which we do only in rust debug builds
+        #[cfg(debug_assertions)]
+        libs.push("aziotsharedutil".to_string());
+        #[cfg(debug_assertions)]
+        libs.push("utpm".to_string());
+
+        if use_enclave {
+            libs.push("oesocket_host".to_string());
+            libs.push("oestdio_host".to_string());
+            libs.push("oehost".to_string());
+
+            if !target_is_windows(&target) {
+                libs.push("teec".to_string());
+            }
+        }
+
+        if target_is_windows(&target) {
+            link_openssl_windows(&target);
+
+            println!(
+                "cargo:rustc-link-search=native={}/bin/x64/Release",
+                env::var("SGXSDKInstallPath").unwrap()
+            );
+
+            if use_enclave {
+                if fs::copy(
+                    format!("{}/bin/enc.signed.dll", iothsm.display()),
+                    format!("{}/../../../deps/enc.signed.dll", iothsm.display())).is_err() {
+                    panic!("Failed to copy enclave to output directory");
+                }
+
+                libs.push("sgx_urts_sim".to_string());
+                libs.push("sgx_uae_service_sim".to_string());
+                libs.push("sgx_uprotected_fs".to_string());
+            }
+        } else {
+            libs.push("crypto".to_string());
+        }
+
+        // For libraries which will just install in target directory
+        println!("cargo:rustc-link-search=native={}", iothsm.display());
+        // For libraries (ie. C Shared) which will install in $target/lib
+        println!("cargo:rustc-link-search=native={}/lib", iothsm.display());
+        println!("cargo:rustc-link-search=native={}/lib64", iothsm.display());
+
+        Artifacts {
+            lib_dir: iothsm.join("lib"),
+            include_dir: iothsm.join("include"),
+            libs,
+        }
+    }
+}
+
+impl Artifacts {
+    pub fn lib_dir(&self) -> &Path {
+        &self.lib_dir
     }
 
-    #[cfg(unix)]
-    println!("cargo:rustc-link-lib=crypto");
+    pub fn include_dir(&self) -> &Path {
+        &self.include_dir
+    }
+
+    pub fn libs(&self) -> &[String] {
+        &self.libs
+    }
+}
+
+fn main() {
+    let artifacts = Build::new().build();
+
+    println!(
+        "cargo:rustc-link-search=native={}",
+        artifacts.lib_dir().display()
+    );
+    for lib in artifacts.libs() {
+        println!("cargo:rustc-link-lib={}", lib);
+    }
+    println!("cargo:include={}", artifacts.include_dir().display());
 }
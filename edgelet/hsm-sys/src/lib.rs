@@ -0,0 +1,4 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+//! Links the native `iothsm` library that `build.rs` builds or locates; this
+//! crate declares no Rust bindings of its own.